@@ -3,65 +3,253 @@ extern crate nom;
 
 use nom::{alpha, alphanumeric, multispace, eof};
 use nom::IResult::*;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 
+/// The output format a template renders to, which selects how
+/// interpolated values are escaped (see `template_utils::Content`).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Format {
+    Html,
+    Text,
+    Json,
+}
+
+impl Format {
+    /// The `Content` method to call for a value interpolated into a
+    /// template of this format.
+    fn method_name(&self) -> &'static str {
+        match *self {
+            Format::Html => "to_html",
+            Format::Text => "to_text",
+            Format::Json => "to_json",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 struct Template {
     preamble: Vec<String>,
+    extends: Option<String>,
+    format: Option<Format>,
     args: Vec<String>,
     body: Vec<TemplateExpression>,
 }
 
 impl Template {
-    fn write_rust(&self, out: &mut Write, name: &str) -> io::Result<()> {
+    /// Writes the generated Rust function for this template.
+    ///
+    /// `preamble`/`args` are the function's preamble lines and formal
+    /// arguments, and `body` is the *resolved* body to render: for a
+    /// template with no `@extends`, these are simply `&self.preamble`/
+    /// `&self.args`/`&self.body`, but for one that extends a parent,
+    /// they are the root ancestor's, since that's whose non-`@block`
+    /// content (and thus whose argument names) ends up in the resolved
+    /// body (see `resolve_template`). `format` is the final output
+    /// format for this template, resolved from its `@format` directive
+    /// or its file suffix.
+    fn write_rust(out: &mut Write,
+                  name: &str,
+                  preamble: &[String],
+                  args: &[String],
+                  body: &[TemplateExpression],
+                  format: Format)
+                  -> io::Result<()> {
         write!(out,
                "{preamble}\n\
                 pub fn {name}(out: &mut Write{args}) -> io::Result<()> {{\n\
                 {body}\
                 Ok(())\n\
                 }}",
-               preamble = self.preamble
+               preamble = preamble
                    .iter()
                    .map(|l| format!("{};\n", l))
                    .collect::<String>(),
                name = name,
-               args = self.args
+               args = args
                    .iter()
                    .map(|a| format!(", {}", a))
                    .collect::<String>(),
-               body = self.body
-                   .iter()
-                   .map(|b| b.code())
+               body = body.iter()
+                   .map(|b| b.code(format))
                    .collect::<String>())
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Collects the top-level `@block`s of a resolved template body, keyed
+/// by block name, so a child template's blocks can be substituted into
+/// its parent's.
+fn collect_blocks(body: &[TemplateExpression]) -> HashMap<String, Vec<TemplateExpression>> {
+    body.iter()
+        .filter_map(|e| match *e {
+            TemplateExpression::Block { ref name, ref body } => Some((name.clone(), body.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replaces each top-level `@block` in `body` with its override from
+/// `overrides`, if any, keeping the parent's default otherwise.
+fn substitute_blocks(body: Vec<TemplateExpression>,
+                      overrides: &HashMap<String, Vec<TemplateExpression>>)
+                      -> Vec<TemplateExpression> {
+    body.into_iter()
+        .map(|e| match e {
+            TemplateExpression::Block { name, body } => {
+                let body = overrides.get(&name).cloned().unwrap_or(body);
+                TemplateExpression::Block { name: name, body: body }
+            }
+            other => other,
+        })
+        .collect()
+}
+
+/// Resolves the `@extends` chain of `name` into the root ancestor (whose
+/// preamble and args the generated function's signature must match,
+/// since the resolved body is mostly the root's own content) and the
+/// final body to render for `name`: the root's body, with each
+/// descendant's `@block` overrides applied from the root down to `name`
+/// itself.
+///
+/// Warns (via `cargo:warning=`) and returns `None`, rather than
+/// panicking or recursing forever, if the chain references a template
+/// that isn't in `templates` or cycles back on itself.
+fn resolve_template<'t>(name: &str,
+                        templates: &'t HashMap<String, Template>)
+                        -> Option<(&'t Template, Vec<TemplateExpression>)> {
+    let mut chain = vec![name.to_string()];
+    let mut t = &templates[name];
+    while let Some(ref parent) = t.extends {
+        if chain.contains(parent) {
+            println!("cargo:warning=@extends cycle detected: {} -> {}",
+                     chain.join(" -> "),
+                     parent);
+            return None;
+        }
+        let parent_t = match templates.get(parent) {
+            Some(p) => p,
+            None => {
+                println!("cargo:warning=Template {:?} extends unknown template {:?}",
+                         chain.last().unwrap(),
+                         parent);
+                return None;
+            }
+        };
+        chain.push(parent.clone());
+        t = parent_t;
+    }
+    let root = t;
+    let mut body = root.body.clone();
+    for child_name in chain.iter().rev().skip(1) {
+        body = substitute_blocks(body, &collect_blocks(&templates[child_name].body));
+    }
+    Some((root, body))
+}
+
+/// Warns (via `cargo:warning=`) about any `@:name(...)` call whose
+/// target isn't one of the templates being compiled.
+fn check_calls(body: &[TemplateExpression], names: &[&str]) {
+    for e in body {
+        match *e {
+            TemplateExpression::Call { ref name, .. } => {
+                if !names.contains(&name.as_str()) {
+                    println!("cargo:warning=Template calls unknown template {:?}", name);
+                }
+            }
+            TemplateExpression::ForLoop { ref body, .. } => check_calls(body, names),
+            TemplateExpression::Conditional { ref then_body, ref else_body, .. } => {
+                check_calls(then_body, names);
+                check_calls(else_body, names);
+            }
+            TemplateExpression::Block { ref body, .. } => check_calls(body, names),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 enum TemplateExpression {
     Comment,
     Text { text: String },
     Expression { expr: String },
+    ForLoop { expr: String, body: Vec<TemplateExpression> },
+    Conditional {
+        test: String,
+        then_body: Vec<TemplateExpression>,
+        else_body: Vec<TemplateExpression>,
+    },
+    Block { name: String, body: Vec<TemplateExpression> },
+    Call { name: String, args: Vec<String> },
 }
 
 impl TemplateExpression {
-    fn code(&self) -> String {
+    /// Generates the Rust statements for this expression. `format` is
+    /// the enclosing template's output format, used to pick which
+    /// `Content` method interpolated values are rendered through.
+    fn code(&self, format: Format) -> String {
         match *self {
             TemplateExpression::Comment => String::new(),
             TemplateExpression::Text { ref text } => {
-                format!("try!(write!(out, \"{}\"));\n", text)
+                format!("try!(write!(out, \"{}\"));\n", escape_rust_string(text))
             }
             TemplateExpression::Expression { ref expr } => {
-                format!("try!({}.to_html(out));\n", expr)
+                format!("try!({}.{}(out));\n", expr, format.method_name())
+            }
+            TemplateExpression::ForLoop { ref expr, ref body } => {
+                format!("for {} {{\n{}}}\n",
+                        expr,
+                        body.iter().map(|b| b.code(format)).collect::<String>())
+            }
+            TemplateExpression::Conditional { ref test, ref then_body, ref else_body } => {
+                format!("if {} {{\n{}}} else {{\n{}}}\n",
+                        test,
+                        then_body.iter().map(|b| b.code(format)).collect::<String>(),
+                        else_body.iter().map(|b| b.code(format)).collect::<String>())
+            }
+            TemplateExpression::Block { ref body, .. } => {
+                body.iter().map(|b| b.code(format)).collect::<String>()
             }
+            TemplateExpression::Call { ref name, ref args } => {
+                format!("try!({}(out{}));\n",
+                        name,
+                        args.iter().map(|a| format!(", {}", a)).collect::<String>())
+            }
+        }
+    }
+}
+
+/// Escapes `text` for embedding as the format string of the generated
+/// `write!(out, "...")` calls, so literal text containing
+/// `"`/`\`/`{`/`}`/control bytes produces valid Rust rather than either
+/// breaking out of the string literal or being parsed by `write!` itself
+/// as a format placeholder. Raw newlines are left as-is, since Rust
+/// string literals may span multiple lines.
+fn escape_rust_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '{' => out.push_str("{{"),
+            '}' => out.push_str("}}"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 && c != '\n' => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
         }
     }
+    out
 }
 
 named!(template<&[u8], Template>,
        chain!(
+           spacelike ~
+           extends: opt!(extends_declaration) ~
+           spacelike ~
+           format: call!(format_declaration_opt) ~
            spacelike ~
            preamble: many0!(chain!(tag!("@") ~
                                    code: is_not!(";()") ~
@@ -75,10 +263,73 @@ named!(template<&[u8], Template>,
            spacelike ~
            body: many0!(template_expression) ~
            eof,
-           || { Template { preamble: preamble, args: args, body: body } }
+           || {
+               Template {
+                   preamble: preamble,
+                   extends: extends,
+                   format: format,
+                   args: args,
+                   body: body,
+               }
+           }
            )
 );
 
+/// Parses a `@extends("name");` preamble directive, declaring the
+/// template whose `@block`s this template may override.
+named!(extends_declaration<&[u8], String>,
+       chain!(
+           tag!("@extends(\"") ~
+           name: is_not!("\"") ~
+           tag!("\")") ~
+           tag!(";") ~
+           spacelike,
+           || from_utf8(name).unwrap().to_string()
+       )
+);
+
+/// Parses a `@format json;` preamble directive, overriding the output
+/// format that would otherwise be inferred from the template's file
+/// suffix.
+named!(format_declaration<&[u8], Format>,
+       chain!(
+           tag!("@format") ~
+           multispace ~
+           format: alt!(
+               map!(tag!("html"), |_| Format::Html) |
+               map!(tag!("text"), |_| Format::Text) |
+               map!(tag!("json"), |_| Format::Json)
+           ) ~
+           tag!(";") ~
+           spacelike,
+           || format
+       )
+);
+
+/// Parses an optional `@format ...;` preamble directive. Unlike
+/// `opt!(format_declaration)`, once the input is actually seen to start
+/// with the `@format` keyword, an unrecognized value (e.g. `@format
+/// xml;`, a plausible typo of `html`/`text`/`json`) is a hard parse
+/// error instead of silently falling through to the generic `@...;`
+/// preamble rule below, which would otherwise splice the bare,
+/// unrecognized directive verbatim into the generated Rust function as
+/// an opaque (and invalid) statement.
+fn format_declaration_opt(input: &[u8]) -> nom::IResult<&[u8], Option<Format>> {
+    let starts_format_directive = input.starts_with(b"@format") &&
+        match input.get(b"@format".len()) {
+            Some(b) => b.is_ascii_whitespace(),
+            None => false,
+        };
+    if !starts_format_directive {
+        return Done(input, None);
+    }
+    match format_declaration(input) {
+        Done(rest, format) => Done(rest, Some(format)),
+        Error(err) => Error(err),
+        Incomplete(needed) => Incomplete(needed),
+    }
+}
+
 // TODO Actually parse arguments!
 named!(formal_argument<&[u8], String>,
        chain!(
@@ -87,34 +338,332 @@ named!(formal_argument<&[u8], String>,
                )
        );
 
-named!(template_expression<&[u8], TemplateExpression>,
-       alt!(
-           chain!(
-               comment,
-               || TemplateExpression::Comment
-               ) |
-           chain!(
-               text: is_not!("@"),
-               || TemplateExpression::Text {
-                   text: from_utf8(text).unwrap().to_string()
-               }) |
-           chain!(
-               tag!("@") ~
-               expr: expression,
-               || TemplateExpression::Expression{ expr: expr }
-           )
+/// Once a `@for`/`@if`/`@block` keyword is recognized, commits to that
+/// construct's parser and propagates its exact result — including an
+/// `Error` from a missing closing `}` — instead of letting
+/// `template_expression`/`nested_template_expression`'s `alt!` move on
+/// to the generic `@` + expression rule below, which would otherwise
+/// silently reinterpret e.g. an unclosed `@if cond {` as the bare
+/// identifier expression `if` followed by stray text.
+fn committed_construct(input: &[u8]) -> Option<nom::IResult<&[u8], TemplateExpression>> {
+    let starts_keyword = |kw: &[u8]| {
+        input.len() > kw.len() && input.starts_with(kw) &&
+            input[kw.len()].is_ascii_whitespace()
+    };
+    if starts_keyword(b"@for") {
+        Some(for_loop(input))
+    } else if starts_keyword(b"@if") {
+        Some(if_conditional(input))
+    } else if starts_keyword(b"@block") {
+        Some(block(input))
+    } else {
+        None
+    }
+}
+
+/// Top-level template expressions: text here is never inside an
+/// enclosing `@for`/`@if`/`@block`, so it runs up to the next `@` only,
+/// same as before `@for`/`@if` were added — a literal unbalanced `}`
+/// in plain text (JS/CSS snippets, prose, malformed-looking JSON
+/// examples) is just text. Use `nested_template_expression` for bodies
+/// that do have an enclosing `}` to find.
+fn template_expression(input: &[u8]) -> nom::IResult<&[u8], TemplateExpression> {
+    if let Some(result) = committed_construct(input) {
+        return result;
+    }
+    alt!(input,
+         chain!(
+             comment,
+             || TemplateExpression::Comment
+             ) |
+         call |
+         chain!(
+             text: is_not!("@"),
+             || TemplateExpression::Text {
+                 text: from_utf8(text).unwrap().to_string()
+             }) |
+         chain!(
+             tag!("@") ~
+             expr: expression ~
+             filters: many0!(complete!(call!(filter))),
+             || TemplateExpression::Expression{
+                 expr: filters.iter().fold(expr, |v, f| f.apply(v))
+             }
+         )
+    )
+}
+
+/// Like `template_expression`, but for use inside a `@for`/`@if`/
+/// `@block` body, where text must stop at the `}` that closes the
+/// enclosing construct rather than running past it.
+fn nested_template_expression(input: &[u8]) -> nom::IResult<&[u8], TemplateExpression> {
+    if let Some(result) = committed_construct(input) {
+        return result;
+    }
+    alt!(input,
+         chain!(
+             comment,
+             || TemplateExpression::Comment
+             ) |
+         call |
+         chain!(
+             text: call!(nested_body_text),
+             || TemplateExpression::Text {
+                 text: from_utf8(text).unwrap().to_string()
+             }) |
+         chain!(
+             tag!("@") ~
+             expr: expression ~
+             filters: many0!(complete!(call!(filter))),
+             || TemplateExpression::Expression{
+                 expr: filters.iter().fold(expr, |v, f| f.apply(v))
+             }
+         )
+    )
+}
+
+/// A `| name` or `| name(args)` link in a filter pipeline, e.g.
+/// `@raw_html | safe` or `@price | round(2)`.
+struct Filter {
+    name: String,
+    args: Vec<String>,
+}
+
+impl Filter {
+    /// Wraps `value` in this filter's call, turning `@v | a | b(x)`
+    /// into the nested calls `b(a(v), x)`. The built-in `safe` filter
+    /// is special-cased to wrap the value in `Html(..)` instead of
+    /// calling a `template_utils` function.
+    fn apply(&self, value: String) -> String {
+        if self.name == "safe" {
+            format!("Html({})", value)
+        } else {
+            format!("{}({}{})",
+                    self.name,
+                    value,
+                    self.args.iter().map(|a| format!(", {}", a)).collect::<String>())
+        }
+    }
+}
+
+named!(filter<&[u8], Filter>,
+       chain!(
+           spacelike ~
+           tag!("|") ~
+           spacelike ~
+           name: rust_name ~
+           args: opt!(arglist),
+           || Filter { name: name, args: args.unwrap_or_else(Vec::new) }
        )
 );
 
+/// Parses a `@:name(arg, arg)` call to another template in the same
+/// `mod templates`, used to factor out shared fragments (e.g. a nav
+/// bar or a list-item renderer) as ordinary partials.
+named!(call<&[u8], TemplateExpression>,
+       chain!(
+           tag!("@:") ~
+           name: rust_name ~
+           tag!("(") ~
+           args: separated_list!(tag!(", "), expression) ~
+           tag!(")"),
+           || TemplateExpression::Call { name: name, args: args }
+       )
+);
+
+/// Text content of a `@for`/`@if`/`@block` body: runs up to the next
+/// `@` expression or a `}` that closes the enclosing block, while
+/// letting any `{`/`}` pairs balanced within the text itself (e.g.
+/// inline CSS) pass through untouched. `@` always ends a text run, even
+/// inside such a pair, and the brace depth is counted afresh for each
+/// run: in a pair split by an interpolation, like `{"key": "@value"}`,
+/// the `}` after `@value` is taken as the `}` that closes the enclosing
+/// block.
+fn nested_body_text(input: &[u8]) -> nom::IResult<&[u8], &[u8]> {
+    let mut depth = 0isize;
+    for (i, &b) in input.iter().enumerate() {
+        match b {
+            b'@' => {
+                return if i == 0 {
+                    Error(nom::Err::Position(nom::ErrorKind::IsNot, input))
+                } else {
+                    Done(&input[i..], &input[..i])
+                };
+            }
+            b'{' => depth += 1,
+            b'}' if depth == 0 => {
+                return if i == 0 {
+                    Error(nom::Err::Position(nom::ErrorKind::IsNot, input))
+                } else {
+                    Done(&input[i..], &input[..i])
+                };
+            }
+            b'}' => depth -= 1,
+            _ => (),
+        }
+    }
+    if input.is_empty() {
+        Error(nom::Err::Position(nom::ErrorKind::IsNot, input))
+    } else {
+        Done(&b""[..], input)
+    }
+}
 
+/// Captures the Rust header of a `@for .. {` / `@if .. {` construct: all
+/// the bytes up to (but not including) the opening brace.
+fn take_block_header(input: &[u8]) -> nom::IResult<&[u8], &[u8]> {
+    match input.iter().position(|&b| b == b'{') {
+        Some(0) => Error(nom::Err::Position(nom::ErrorKind::TakeUntil, input)),
+        Some(i) => Done(&input[i..], &input[..i]),
+        None => Incomplete(nom::Needed::Unknown),
+    }
+}
+
+named!(for_loop<&[u8], TemplateExpression>,
+       chain!(
+           tag!("@for") ~
+           multispace ~
+           expr: complete!(call!(take_block_header)) ~
+           tag!("{") ~
+           body: many0!(nested_template_expression) ~
+           complete!(tag!("}")),
+           || TemplateExpression::ForLoop {
+               expr: from_utf8(expr).unwrap().trim().to_string(),
+               body: body,
+           }
+       )
+);
+
+named!(if_conditional<&[u8], TemplateExpression>,
+       chain!(
+           tag!("@if") ~
+           multispace ~
+           test: complete!(call!(take_block_header)) ~
+           tag!("{") ~
+           then_body: many0!(nested_template_expression) ~
+           complete!(tag!("}")) ~
+           else_body: opt!(chain!(
+               spacelike ~
+               tag!("@else") ~
+               spacelike ~
+               body: alt!(
+                   chain!(c: if_conditional, || vec![c]) |
+                   chain!(tag!("{") ~
+                          b: many0!(nested_template_expression) ~
+                          complete!(tag!("}")),
+                          || b)
+               ),
+               || body
+           )),
+           || TemplateExpression::Conditional {
+               test: from_utf8(test).unwrap().trim().to_string(),
+               then_body: then_body,
+               else_body: else_body.unwrap_or_else(Vec::new),
+           }
+       )
+);
+
+/// Parses a `@block name { ... }` region: a named placeholder that a
+/// child template (one that `@extends` this one) may override.
+named!(block<&[u8], TemplateExpression>,
+       chain!(
+           tag!("@block") ~
+           multispace ~
+           name: rust_name ~
+           spacelike ~
+           tag!("{") ~
+           body: many0!(nested_template_expression) ~
+           complete!(tag!("}")),
+           || TemplateExpression::Block { name: name, body: body }
+       )
+);
+
+
+/// A small recursive-descent parser for the subset of Rust expressions
+/// that can be interpolated with `@`: a primary (identifier, integer or
+/// string literal, or a parenthesized sub-expression) followed by any
+/// number of postfix operators (`.field`, `.method(args)`, `(args)`,
+/// `[index]`). The result is a `String` of valid Rust to splice into
+/// the template format's `Content` call, e.g. `.to_html(out)`.
 named!(expression<&[u8], String>,
+       chain!(
+           pre: primary_expression ~
+           post: many0!(postfix_operation),
+           || post.into_iter().fold(pre, |base, p| p.apply(base))
+           ));
+
+enum Postfix {
+    Field(String),
+    Method(String, Vec<String>),
+    Call(Vec<String>),
+    Index(String),
+}
+
+impl Postfix {
+    fn apply(self, base: String) -> String {
+        match self {
+            Postfix::Field(name) => format!("{}.{}", base, name),
+            Postfix::Method(name, args) => format!("{}.{}({})", base, name, args.join(", ")),
+            Postfix::Call(args) => format!("{}({})", base, args.join(", ")),
+            Postfix::Index(idx) => format!("{}[{}]", base, idx),
+        }
+    }
+}
+
+named!(postfix_operation<&[u8], Postfix>,
+       alt!(
+           chain!(char!('.') ~
+                  name: rust_name ~
+                  args: opt!(arglist),
+                  || match args {
+                      Some(args) => Postfix::Method(name, args),
+                      None => Postfix::Field(name),
+                  }) |
+           chain!(args: arglist, || Postfix::Call(args)) |
+           chain!(char!('[') ~
+                  idx: expression ~
+                  char!(']'),
+                  || Postfix::Index(idx))
+       )
+);
+
+named!(arglist<&[u8], Vec<String>>,
+       delimited!(char!('('),
+                  separated_list!(tag!(", "), expression),
+                  char!(')'))
+);
+
+named!(primary_expression<&[u8], String>,
        alt!(
-           chain!(pre: rust_name ~
-                  char!('.') ~
-                  post: expression,
-                  || format!("{}.{}", pre, post)) |
+           chain!(char!('(') ~
+                  e: expression ~
+                  char!(')'),
+                  || format!("({})", e)) |
+           string_literal |
+           integer_literal |
            rust_name
-               ));
+       )
+);
+
+named!(string_literal<&[u8], String>,
+       chain!(
+           raw: recognize!(delimited!(
+               char!('"'),
+               many0!(alt!(
+                   chain!(char!('\\') ~ take!(1), || ()) |
+                   chain!(none_of!("\"\\"), || ())
+               )),
+               char!('"'))),
+           || from_utf8(raw).unwrap().to_string()
+       )
+);
+
+named!(integer_literal<&[u8], String>,
+       chain!(
+           raw: is_a!("0123456789"),
+           || from_utf8(raw).unwrap().to_string()
+       )
+);
 
 #[test]
 fn test_expression() {
@@ -138,9 +687,83 @@ fn test_expression() {
     }
 }
 
+#[test]
+fn test_expression_calls_and_indexing() {
+    assert_eq!(expression(b"user.name()  "),
+               Done(&b"  "[..], "user.name()".to_string()));
+    assert_eq!(expression(b"format_price(total)  "),
+               Done(&b"  "[..], "format_price(total)".to_string()));
+    assert_eq!(expression(b"items[0]  "),
+               Done(&b"  "[..], "items[0]".to_string()));
+    assert_eq!(expression(b"x.trim().to_uppercase()  "),
+               Done(&b"  "[..], "x.trim().to_uppercase()".to_string()));
+}
+
+fn parse_ok(src: &[u8]) -> Template {
+    match template(src) {
+        Done(_, t) => t,
+        other => panic!("expected a successful parse, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_resolve_template_uses_root_args_and_child_blocks() {
+    let mut templates = HashMap::new();
+    templates.insert("base".to_string(),
+                      parse_ok(b"@(title: &str)\n<title>@title</title>\n\
+                                 @block content {\ndefault\n}\n"));
+    templates.insert("child".to_string(),
+                      parse_ok(b"@extends(\"base\");\n@()\n\
+                                 @block content {\nhello\n}\n"));
+    let (root, body) = resolve_template("child", &templates).expect("chain should resolve");
+    let mut out = Vec::new();
+    Template::write_rust(&mut out, "child", &root.preamble, &root.args, &body, Format::Html)
+        .unwrap();
+    let code = String::from_utf8(out).unwrap();
+    assert!(code.contains("pub fn child(out: &mut Write, title: &str)"),
+            "signature should use the root template's args, got:\n{}",
+            code);
+    assert!(code.contains("hello"), "child's block override should be used, got:\n{}", code);
+    assert!(!code.contains("default"),
+            "parent's default block should be overridden, got:\n{}",
+            code);
+}
+
+#[test]
+fn test_resolve_template_missing_parent_returns_none() {
+    let mut templates = HashMap::new();
+    templates.insert("child".to_string(),
+                      parse_ok(b"@extends(\"missing\");\n@()\nhi\n"));
+    assert!(resolve_template("child", &templates).is_none());
+}
+
+#[test]
+fn test_resolve_template_cycle_returns_none() {
+    let mut templates = HashMap::new();
+    templates.insert("a".to_string(), parse_ok(b"@extends(\"b\");\n@()\nA\n"));
+    templates.insert("b".to_string(), parse_ok(b"@extends(\"a\");\n@()\nB\n"));
+    assert!(resolve_template("a", &templates).is_none());
+}
+
+#[test]
+fn test_format_declaration_opt_recognizes_known_formats() {
+    assert_eq!(format_declaration_opt(b"@format json;\nrest"),
+               Done(&b"rest"[..], Some(Format::Json)));
+    assert_eq!(format_declaration_opt(b"no format directive here"),
+               Done(&b"no format directive here"[..], None));
+}
+
+#[test]
+fn test_format_declaration_opt_rejects_unknown_format() {
+    match format_declaration_opt(b"@format xml;\n@()\nhi\n") {
+        Error(_) => (),
+        other => panic!("expected a parse error for an unknown @format value, got {:?}", other),
+    }
+}
+
 named!(rust_name<&[u8], String>,
-       chain!(first: alpha ~
-              rest: opt!(alphanumeric),
+       chain!(first: alt!(alpha | tag!("_")) ~
+              rest: opt!(is_a!("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_")),
               || format!("{}{}",
                          from_utf8(first).unwrap(),
                          from_utf8(rest.unwrap_or(b"")).unwrap())));
@@ -189,6 +812,46 @@ fn test_comment6() {
                Done(&b"***"[..], ()));
 }
 
+/// Locates the source file for template `name`, trying each supported
+/// suffix in turn, and returns it along with the `Format` that suffix
+/// implies (overridden by a `@format` directive, if the template has
+/// one).
+fn find_template_file(indir: &Path, name: &str) -> Option<(PathBuf, Format)> {
+    for &(suffix, format) in &[(".rs.html", Format::Html),
+                                (".rs.txt", Format::Text),
+                                (".rs.json", Format::Json)] {
+        let path = indir.join(format!("{}{}", name, suffix));
+        if path.exists() {
+            return Some((path, format));
+        }
+    }
+    None
+}
+
+/// Test-only entry point for the fixture-based grammar harness in
+/// `tests/test_syntax.rs`: parses `src` as a single template (named
+/// `name`) and renders either its generated Rust function or a
+/// formatted parse error, so a snapshot test can assert on either.
+///
+/// This does not resolve `@extends` chains, since fixtures are
+/// standalone templates; a child template's `@block`s are rendered as
+/// written, uncombined with any parent.
+#[doc(hidden)]
+pub fn parse_for_test(name: &str, src: &[u8]) -> Result<String, String> {
+    match template(src) {
+        Done(_, t) => {
+            let format = t.format.unwrap_or(Format::Html);
+            let body = t.body.clone();
+            let mut out = Vec::new();
+            try!(Template::write_rust(&mut out, name, &t.preamble, &t.args, &body, format)
+                .map_err(|e| e.to_string()));
+            Ok(String::from_utf8(out).unwrap())
+        }
+        Error(err) => Err(format!("{}", err)),
+        Incomplete(needed) => Err(format!("{:?} needed", needed)),
+    }
+}
+
 pub fn compile_templates(indir: &Path,
                          outdir: &Path,
                          names: &[&str])
@@ -197,14 +860,25 @@ pub fn compile_templates(indir: &Path,
         try!(write!(f, "mod templates {{\n\
                         use std::io::{{self, Write}};\n\
                         use std::fmt::Display;\n"));
+        let mut templates = HashMap::new();
+        let mut formats = HashMap::new();
         for name in names {
-            let path = indir.join(format!("{}.rs.html", name));
+            let (path, inferred_format) = match find_template_file(indir, name) {
+                Some(found) => found,
+                None => {
+                    println!("cargo:warning=No template file found for {:?}", name);
+                    continue;
+                }
+            };
             println!("cargo:rerun-if-changed={}", path.to_string_lossy());
             let mut input = try!(File::open(&path));
             let mut buf = Vec::new();
             try!(input.read_to_end(&mut buf));
             match template(&buf) {
-                Done(_, t) => try!(t.write_rust(&mut f, name)),
+                Done(_, t) => {
+                    formats.insert(name.to_string(), t.format.unwrap_or(inferred_format));
+                    templates.insert(name.to_string(), t);
+                }
                 Error(err) => {
                     println!("cargo:warning=Template parse error in {:?}: {}",
                              path, err)
@@ -216,6 +890,19 @@ pub fn compile_templates(indir: &Path,
                 }
             }
         }
+        for name in names {
+            if templates.contains_key(*name) {
+                if let Some((root, body)) = resolve_template(name, &templates) {
+                    check_calls(&body, names);
+                    try!(Template::write_rust(&mut f,
+                                              name,
+                                              &root.preamble,
+                                              &root.args,
+                                              &body,
+                                              formats[*name]));
+                }
+            }
+        }
         write!(f, "{}\n}}\n", include_str!(concat!(env!("CARGO_MANIFEST_DIR"),
                                                    "/src/template_utils.rs")))
     })
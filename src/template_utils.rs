@@ -0,0 +1,134 @@
+/// Something that can be written to a template's output in any of the
+/// supported target formats, escaping anything that isn't already known
+/// to be safe for that format.
+///
+/// A template picks one format (`@format json;`, or inferred from its
+/// file suffix); the generated function then calls the matching method
+/// on every interpolated value.
+pub trait Content {
+    fn to_html(&self, out: &mut Write) -> io::Result<()>;
+    fn to_text(&self, out: &mut Write) -> io::Result<()>;
+    fn to_json(&self, out: &mut Write) -> io::Result<()>;
+}
+
+impl Content for str {
+    fn to_html(&self, out: &mut Write) -> io::Result<()> {
+        write!(out, "{}", encode_html(self))
+    }
+    fn to_text(&self, out: &mut Write) -> io::Result<()> {
+        write!(out, "{}", self)
+    }
+    fn to_json(&self, out: &mut Write) -> io::Result<()> {
+        write!(out, "{}", encode_json(self))
+    }
+}
+
+impl<'a> Content for &'a str {
+    fn to_html(&self, out: &mut Write) -> io::Result<()> {
+        (**self).to_html(out)
+    }
+    fn to_text(&self, out: &mut Write) -> io::Result<()> {
+        (**self).to_text(out)
+    }
+    fn to_json(&self, out: &mut Write) -> io::Result<()> {
+        (**self).to_json(out)
+    }
+}
+
+impl Content for String {
+    fn to_html(&self, out: &mut Write) -> io::Result<()> {
+        self.as_str().to_html(out)
+    }
+    fn to_text(&self, out: &mut Write) -> io::Result<()> {
+        self.as_str().to_text(out)
+    }
+    fn to_json(&self, out: &mut Write) -> io::Result<()> {
+        self.as_str().to_json(out)
+    }
+}
+
+macro_rules! impl_content_for_display {
+    ($($ty:ty),*) => {
+        $(impl Content for $ty {
+            fn to_html(&self, out: &mut Write) -> io::Result<()> {
+                write!(out, "{}", encode_html(&self.to_string()))
+            }
+            fn to_text(&self, out: &mut Write) -> io::Result<()> {
+                write!(out, "{}", self)
+            }
+            fn to_json(&self, out: &mut Write) -> io::Result<()> {
+                write!(out, "{}", encode_json(&self.to_string()))
+            }
+        })*
+    }
+}
+impl_content_for_display!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64, bool);
+
+/// Wraps an already-safe fragment so it is written out verbatim instead
+/// of being escaped again, e.g. for the `| safe` filter or raw markup
+/// from a trusted source. Bypasses escaping in every format.
+pub struct Html<T>(pub T);
+
+impl<T: Display> Content for Html<T> {
+    fn to_html(&self, out: &mut Write) -> io::Result<()> {
+        write!(out, "{}", self.0)
+    }
+    fn to_text(&self, out: &mut Write) -> io::Result<()> {
+        write!(out, "{}", self.0)
+    }
+    fn to_json(&self, out: &mut Write) -> io::Result<()> {
+        write!(out, "{}", self.0)
+    }
+}
+
+fn encode_html(raw: &str) -> String {
+    raw.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn encode_json(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `| lower` filter: lower-cases the value.
+pub fn lower<T: Display>(value: T) -> String {
+    value.to_string().to_lowercase()
+}
+
+/// `| upper` filter: upper-cases the value.
+pub fn upper<T: Display>(value: T) -> String {
+    value.to_string().to_uppercase()
+}
+
+/// `| trim` filter: strips leading/trailing whitespace.
+pub fn trim<T: Display>(value: T) -> String {
+    value.to_string().trim().to_string()
+}
+
+/// `| urlencode` filter: percent-encodes for use in a query string or
+/// attribute value.
+pub fn urlencode<T: Display>(value: T) -> String {
+    let mut out = String::new();
+    for b in value.to_string().bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
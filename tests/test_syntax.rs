@@ -0,0 +1,97 @@
+//! Fixture-based regression tests for the template grammar.
+//!
+//! Each `tests/fixtures/<name>.rs.html` is parsed in isolation (no
+//! `@extends` resolution, since fixtures are standalone) and the result
+//! is compared against the committed `tests/fixtures/<name>.expected`:
+//! either the generated Rust function, or `ERROR: <message>` for a
+//! fixture that's intentionally malformed. Add a new case by dropping
+//! in a `.rs.html` fixture and its `.expected` snapshot.
+//!
+//! `generated_code_is_valid_rust` additionally guards against a snapshot
+//! that matches byte-for-byte but isn't actually valid Rust (a string
+//! diff alone can't tell): it assembles every successfully-generated
+//! fixture into one `mod templates { ... }`, exactly as `compile_templates`
+//! does for a real project (same `template_utils.rs` splice, same
+//! function list, so a fixture's `@:name(args)` call to another fixture
+//! resolves for free), and actually compiles it with `rustc`.
+
+extern crate ructe;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn render(path: &Path) -> String {
+    let name = path.file_stem().unwrap().to_str().unwrap().trim_end_matches(".rs");
+    let src = fs::read(path).unwrap();
+    match ructe::parse_for_test(name, &src) {
+        Ok(code) => code,
+        Err(err) => format!("ERROR: {}", err),
+    }
+}
+
+fn fixture_paths() -> Vec<std::path::PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("html"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn fixtures_match_expected_output() {
+    let paths = fixture_paths();
+    for path in &paths {
+        let expected_path = path.with_extension("").with_extension("expected");
+        let expected = fs::read_to_string(&expected_path)
+            .unwrap_or_else(|e| panic!("missing snapshot {:?}: {}", expected_path, e));
+        let actual = render(path);
+        assert_eq!(actual, expected, "mismatch for fixture {:?}", path);
+    }
+    assert!(!paths.is_empty(), "no fixtures found");
+}
+
+#[test]
+fn generated_code_is_valid_rust() {
+    let template_utils = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/template_utils.rs"))
+        .unwrap();
+    let mut src = String::new();
+    src.push_str("mod templates {\n\
+                   use std::io::{self, Write};\n\
+                   use std::fmt::Display;\n");
+    let mut compiled = 0;
+    for path in fixture_paths() {
+        let code = render(&path);
+        if code.starts_with("ERROR: ") {
+            // Intentionally-malformed fixtures (e.g. unclosed_if) have no
+            // generated function to check.
+            continue;
+        }
+        src.push_str(&code);
+        src.push('\n');
+        compiled += 1;
+    }
+    assert!(compiled > 0, "no successfully-generated fixtures to check");
+    src.push_str(&template_utils);
+    src.push_str("}\n");
+
+    let dir = std::env::temp_dir().join(format!("ructe-fixture-check-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let src_path = dir.join("templates.rs");
+    fs::write(&src_path, &src).unwrap();
+    let out_path = dir.join("libtemplates.rlib");
+    let output = Command::new("rustc")
+        .args(&["--edition", "2015", "--crate-type", "lib", "-o"])
+        .arg(&out_path)
+        .arg(&src_path)
+        .output()
+        .expect("failed to run rustc");
+    assert!(output.status.success(),
+            "generated code failed to compile:\n{}\n--- generated source ---\n{}",
+            String::from_utf8_lossy(&output.stderr),
+            src);
+}